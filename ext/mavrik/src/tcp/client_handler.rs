@@ -1,100 +1,232 @@
 use crate::messaging::{MavrikRequest, MavrikResponse, Task, TaskId, TaskResult};
 use crate::service::MavrikService;
-use crate::store::{PullStore, PushStore, QueryStore};
-use crate::tcp::util::{read_deserialized, write_serialized};
+use crate::store::{PullStore, PushStore, QueryStore, SubscribeStore};
+use crate::tcp::util::{read_deserialized, write_serialized, Codec, JsonCodec};
 use anyhow::Context;
+use futures::StreamExt;
 use log::trace;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
 use tokio::net::TcpStream;
 use tokio::select;
 use tokio::task::JoinSet;
 
+/// Default cap on the number of concurrently spawned `AwaitTask` pulls per connection.
+///
+/// Once `task_results` reaches this many in-flight futures, further `AwaitTask` requests are
+/// stashed in `pending_awaits` instead of being spawned immediately, and get spawned as soon as
+/// a slot drains. This only throttles `AwaitTask`; every other request keeps being read and
+/// handled immediately.
+const DEFAULT_MAX_IN_FLIGHT: usize = 64;
+
+/// Hard ceiling on total outstanding `AwaitTask` pulls (spawned plus stashed) per connection.
+///
+/// The soft [`DEFAULT_MAX_IN_FLIGHT`] cap throttles spawning but a client can keep sending
+/// `AwaitTask` requests faster than slots drain, growing `pending_awaits` without bound. This
+/// ceiling is the backstop: once spawned-plus-stashed would cross it, further `AwaitTask`
+/// requests are rejected outright with a `MavrikResponse::Error` instead of being queued.
+const DEFAULT_HARD_CEILING: usize = 128;
+
 #[derive(Debug)]
 pub enum TaskOutputKind {
     Request(MavrikRequest),
-    TaskResult(TaskResult),
+    TaskResult { seq: u64, task_result: TaskResult },
+    SubscribedTaskResult(TaskResult),
 }
 
-pub struct TcpClientHandler<Store> {
+pub struct TcpClientHandler<Store, C = JsonCodec>
+where
+    Store: SubscribeStore<Error = anyhow::Error>,
+{
     stream: TcpStream,
     store: Store,
-    task_results: JoinSet<Result<TaskResult, anyhow::Error>>
+    task_results: JoinSet<Result<(u64, TaskResult), anyhow::Error>>,
+    pending_awaits: VecDeque<(u64, TaskId)>,
+    subscription: Option<Store::Completions>,
+    max_in_flight: usize,
+    hard_ceiling: usize,
+    _codec: PhantomData<C>
 }
 
-impl<Store> TcpClientHandler<Store>
+impl<Store, C> TcpClientHandler<Store, C>
 where
-    Store: PushStore<Id = TaskId, Error = anyhow::Error> 
+    Store: PushStore<Id = TaskId, Error = anyhow::Error>
         + PullStore<Id = TaskId, Error = anyhow::Error>
         + QueryStore<Error = anyhow::Error>
+        + SubscribeStore<Error = anyhow::Error>
         + Clone + Send + Sync + 'static,
-    
+    C: Codec + Send + Sync + 'static,
 {
     pub fn new(stream: TcpStream, store: Store) -> Self {
         let task_results = JoinSet::new();
-        Self { stream, store, task_results }
+        Self {
+            stream,
+            store,
+            task_results,
+            pending_awaits: VecDeque::new(),
+            subscription: None,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            hard_ceiling: DEFAULT_HARD_CEILING,
+            _codec: PhantomData
+        }
+    }
+
+    /// Caps the number of concurrently spawned `AwaitTask` pulls before further ones are stashed,
+    /// and the hard ceiling of spawned-plus-stashed past which a request is rejected outright.
+    /// See [`DEFAULT_MAX_IN_FLIGHT`] and [`DEFAULT_HARD_CEILING`].
+    pub fn with_max_in_flight(mut self, max_in_flight: usize, hard_ceiling: usize) -> Self {
+        self.max_in_flight = max_in_flight;
+        self.hard_ceiling = hard_ceiling;
+        self
     }
-    
+
+    /// Rebinds this handler to a different wire codec, e.g.
+    /// `TcpClientHandler::new(stream, store).with_codec::<MsgpackCodec>()`. `JSON` remains the
+    /// default from [`TcpClientHandler::new`] for backward compatibility.
+    pub fn with_codec<NewC>(self) -> TcpClientHandler<Store, NewC>
+    where
+        NewC: Codec + Send + Sync + 'static,
+    {
+        TcpClientHandler {
+            stream: self.stream,
+            store: self.store,
+            task_results: self.task_results,
+            pending_awaits: self.pending_awaits,
+            subscription: self.subscription,
+            max_in_flight: self.max_in_flight,
+            hard_ceiling: self.hard_ceiling,
+            _codec: PhantomData
+        }
+    }
+
+    /// Spawns a pull for `task_id`/`seq` into `task_results`.
+    fn spawn_await(&mut self, seq: u64, task_id: TaskId) {
+        let store = self.store.clone();
+        self.task_results.spawn(async move {
+            let task_result = store.pull(task_id).await?;
+            Ok((seq, task_result))
+        });
+    }
+
+    /// Spawns stashed `AwaitTask` pulls until `task_results` is back at `max_in_flight` or
+    /// `pending_awaits` runs dry. Called whenever a slot in `task_results` frees up.
+    fn drain_pending_awaits(&mut self) {
+        while self.task_results.len() < self.max_in_flight {
+            let Some((seq, task_id)) = self.pending_awaits.pop_front() else { break };
+            self.spawn_await(seq, task_id);
+        }
+    }
+
+    /// Awaits the next completion pushed by the active subscription, if any.
+    ///
+    /// Used as a `select!` arm that stays pending (rather than resolving immediately) while
+    /// there's no active subscription, so it never wins a `select!` race against the socket or
+    /// `task_results` until a client actually subscribes.
+    async fn next_subscribed(subscription: &mut Option<Store::Completions>) -> Option<Result<TaskResult, anyhow::Error>> {
+        match subscription {
+            Some(stream) => stream.next().await,
+            None => std::future::pending().await,
+        }
+    }
+
     async fn handle_request(&mut self, request: MavrikRequest) -> Result<(), anyhow::Error> {
+        let seq = request.seq();
         match request {
-            MavrikRequest::NewTask(new_task) => {
+            MavrikRequest::NewTask { new_task, .. } => {
                 let task = Task::from(new_task);
                 let task_id = self.store.push(task).await.context("store push failed")?;
-                let response = MavrikResponse::NewTaskId(task_id);
+                let response = MavrikResponse::NewTaskId { seq, task_id };
 
                 trace!(response:?; "Sending response over TCP");
-                write_serialized(&mut self.stream, &response)
+                write_serialized::<C, _, _>(&mut self.stream, &response)
                     .await
                     .context("sending new task ID over TCP failed")?;
             },
 
-            MavrikRequest::AwaitTask { task_id } => {
-                let store = self.store.clone();
-                self.task_results.spawn(async move { store.pull(task_id).await });
+            MavrikRequest::AwaitTask { task_id, .. } => {
+                let outstanding = self.task_results.len() + self.pending_awaits.len();
+                if outstanding >= self.hard_ceiling {
+                    let response = MavrikResponse::Error {
+                        seq,
+                        message: format!("too many outstanding AwaitTask pulls (hard ceiling is {})", self.hard_ceiling)
+                    };
+                    write_serialized::<C, _, _>(&mut self.stream, &response)
+                        .await
+                        .context("sending AwaitTask rejection over TCP failed")?;
+                } else if self.task_results.len() >= self.max_in_flight {
+                    self.pending_awaits.push_back((seq, task_id));
+                } else {
+                    self.spawn_await(seq, task_id);
+                }
             },
 
-            MavrikRequest::GetStoreState => {
+            MavrikRequest::GetStoreState { .. } => {
                 let state = self.store.state().await?;
-                let response = MavrikResponse::StoreState(state);
-                write_serialized(&mut self.stream, &response)
+                let response = MavrikResponse::StoreState { seq, state };
+                write_serialized::<C, _, _>(&mut self.stream, &response)
                     .await
                     .context("sending state over TCP failed")?;
+            },
+
+            MavrikRequest::Subscribe { filter, .. } => {
+                self.subscription = Some(self.store.subscribe(filter));
+            },
+
+            MavrikRequest::Unsubscribe { .. } => {
+                self.subscription = None;
             }
         };
         Ok(())
     }
-    
-    async fn handle_task_result(&mut self, task_result: TaskResult) -> Result<(), anyhow::Error> {
-        let response = MavrikResponse::CompletedTask(task_result);
+
+    async fn handle_task_result(&mut self, seq: u64, task_result: TaskResult) -> Result<(), anyhow::Error> {
+        let response = MavrikResponse::CompletedTask { seq, task_result };
 
         trace!(response:?; "Sending response over TCP");
-        write_serialized(&mut self.stream, &response)
+        write_serialized::<C, _, _>(&mut self.stream, &response)
             .await
             .context("failed to send Mavrik response over TCP")?;
-        
+
+        self.drain_pending_awaits();
         Ok(())
     }
+
+    /// Pushes a `CompletedTask` response for a task surfaced by the active subscription, rather
+    /// than by a one-shot `AwaitTask`. There's no requesting `seq` to echo back, so `0` is used
+    /// as the sentinel for "unsolicited" completions.
+    async fn handle_subscribed_task_result(&mut self, task_result: TaskResult) -> Result<(), anyhow::Error> {
+        self.handle_task_result(0, task_result).await
+    }
 }
 
-impl<Store> MavrikService for TcpClientHandler<Store>
+impl<Store, C> MavrikService for TcpClientHandler<Store, C>
 where
     Store: PushStore<Id = TaskId, Error = anyhow::Error>
         + PullStore<Id = TaskId, Error = anyhow::Error>
         + QueryStore<Error = anyhow::Error>
+        + SubscribeStore<Error = anyhow::Error>
         + Clone + Send + Sync + 'static,
+    C: Codec + Send + Sync + 'static,
 {
     type TaskOutput = Result<TaskOutputKind, anyhow::Error>;
 
     async fn poll_task(&mut self) -> Self::TaskOutput {
         select! {
-            result = read_deserialized(&mut self.stream) => {
+            result = read_deserialized::<C, _, _>(&mut self.stream) => {
                 let request = result.context("receiving Mavrik request over TCP failed")?;
                 Ok(TaskOutputKind::Request(request))
             },
-            
+
             Some(result) = self.task_results.join_next(), if self.task_results.len() > 0 => {
-                let task_result = result
+                let (seq, task_result) = result
                     .context("joining task result failed")?
                     .context("awaiting task failed")?;
-                Ok(TaskOutputKind::TaskResult(task_result))
+                Ok(TaskOutputKind::TaskResult { seq, task_result })
+            },
+
+            Some(result) = Self::next_subscribed(&mut self.subscription), if self.subscription.is_some() => {
+                let task_result = result.context("awaiting subscribed task result failed")?;
+                Ok(TaskOutputKind::SubscribedTaskResult(task_result))
             }
         }
     }
@@ -102,7 +234,84 @@ where
     async fn on_task_ready(&mut self, output: Self::TaskOutput) -> Result<(), anyhow::Error> {
         match output? {
             TaskOutputKind::Request(request) => self.handle_request(request).await,
-            TaskOutputKind::TaskResult(task_result) => self.handle_task_result(task_result).await
+            TaskOutputKind::TaskResult { seq, task_result } => self.handle_task_result(seq, task_result).await,
+            TaskOutputKind::SubscribedTaskResult(task_result) => self.handle_subscribed_task_result(task_result).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tcp::util::JsonCodec;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// A store whose `pull` never resolves, so pulls spawned against it stay in `task_results`
+    /// forever. That's what lets a test push the handler past `hard_ceiling` deterministically.
+    #[derive(Clone)]
+    struct NeverCompletingStore;
+
+    impl PushStore for NeverCompletingStore {
+        type Id = TaskId;
+        type Error = anyhow::Error;
+
+        async fn push(&self, _task: Task) -> Result<TaskId, anyhow::Error> {
+            unreachable!("not exercised by this test")
+        }
+    }
+
+    impl PullStore for NeverCompletingStore {
+        type Id = TaskId;
+        type Error = anyhow::Error;
+
+        async fn pull(&self, _task_id: TaskId) -> Result<TaskResult, anyhow::Error> {
+            std::future::pending().await
+        }
+    }
+
+    impl QueryStore for NeverCompletingStore {
+        type Error = anyhow::Error;
+
+        async fn state(&self) -> Result<crate::messaging::StoreState, anyhow::Error> {
+            unreachable!("not exercised by this test")
+        }
+    }
+
+    impl SubscribeStore for NeverCompletingStore {
+        type Error = anyhow::Error;
+        type Completions = futures::stream::Pending<Result<TaskResult, anyhow::Error>>;
+
+        fn subscribe(&self, _filter: Option<String>) -> Self::Completions {
+            futures::stream::pending()
+        }
+    }
+
+    async fn loopback_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind loopback listener");
+        let addr = listener.local_addr().expect("read loopback addr");
+        let client = TcpStream::connect(addr).await.expect("connect loopback client");
+        let (server, _) = listener.accept().await.expect("accept loopback client");
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn await_task_is_rejected_once_the_hard_ceiling_is_reached() {
+        let (mut client, server) = loopback_pair().await;
+        let mut handler = TcpClientHandler::<_, JsonCodec>::new(server, NeverCompletingStore)
+            .with_max_in_flight(1, 2);
+
+        // First AwaitTask spawns immediately (0 outstanding < max_in_flight of 1).
+        handler.handle_request(MavrikRequest::AwaitTask { seq: 0, task_id: TaskId::default() }).await.unwrap();
+        // Second is stashed rather than spawned (1 outstanding >= max_in_flight of 1), but still
+        // accepted (1 outstanding < hard_ceiling of 2).
+        handler.handle_request(MavrikRequest::AwaitTask { seq: 1, task_id: TaskId::default() }).await.unwrap();
+        // Third crosses the hard ceiling (2 outstanding >= hard_ceiling of 2) and is rejected.
+        handler.handle_request(MavrikRequest::AwaitTask { seq: 2, task_id: TaskId::default() }).await.unwrap();
+
+        let response: MavrikResponse = read_deserialized::<JsonCodec, _, _>(&mut client).await.unwrap();
+        match response {
+            MavrikResponse::Error { seq, .. } => assert_eq!(seq, 2),
+            other => panic!("expected a rejection for the third AwaitTask, got {other:?}"),
         }
     }
 }
@@ -3,47 +3,210 @@ use log::trace;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::fmt::Debug;
-use anyhow::Context;
-
-/// Read and deserialize a string from a stream.
-/// 
-/// The payload of the stream should contain a header of `size_of::<usize>()` bytes (called `len`). This value indicates
-/// the length of the string in the stream. `len` bytes are then read from the stream into a string. This string is
-/// deserialized using `serde_json`.
-/// 
-pub async fn read_deserialized<AR, T>(stream: &mut AR) -> Result<T, anyhow::Error>
+use anyhow::{bail, Context};
+
+/// Maximum size, in bytes, of a single chunk body written by [`write_serialized`].
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Default cap on the total reassembled message size, in bytes.
+///
+/// This guards [`read_deserialized`] against a malicious or misbehaving sender declaring an
+/// effectively unbounded number of chunks before the payload is ever deserialized.
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Flag byte indicating that more chunks follow the one just read.
+const FLAG_MORE: u8 = 0;
+
+/// Flag byte indicating that the chunk just read is the last one in the message.
+const FLAG_FINAL: u8 = 1;
+
+/// A wire codec used to serialize/deserialize values exchanged over a transport.
+///
+/// Implementations are zero-sized marker types dispatched on at compile time via generics, so
+/// [`read_deserialized`]/[`write_serialized`] can be made generic over the chosen codec without
+/// any runtime indirection.
+pub trait Codec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, anyhow::Error>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, anyhow::Error>;
+}
+
+/// The default codec: human-readable, backward-compatible JSON via `serde_json`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, anyhow::Error> {
+        serde_json::to_vec(value).context("serializing payload as JSON")
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, anyhow::Error> {
+        serde_json::from_slice(bytes).context("deserializing payload as JSON")
+    }
+}
+
+/// A compact binary codec using MessagePack via `rmp-serde`, for high-throughput task submission.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgpackCodec;
+
+impl Codec for MsgpackCodec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, anyhow::Error> {
+        rmp_serde::to_vec(value).context("serializing payload as MessagePack")
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, anyhow::Error> {
+        rmp_serde::from_slice(bytes).context("deserializing payload as MessagePack")
+    }
+}
+
+/// Read and deserialize a value from a stream framed as a sequence of chunks.
+///
+/// Each chunk is prefixed with a `u32` big-endian chunk length followed by a 1-byte flag (`0` if
+/// more chunks follow, `1` if this is the final chunk). Chunk bodies are appended to a growing
+/// buffer until the final-chunk flag is seen, at which point the buffer is decoded using `C`.
+/// Reading is capped at [`DEFAULT_MAX_MESSAGE_SIZE`] total bytes; use
+/// [`read_deserialized_with_limit`] to override it.
+///
+pub async fn read_deserialized<C, AR, T>(stream: &mut AR) -> Result<T, anyhow::Error>
 where
+    C: Codec,
     AR: AsyncRead + Unpin,
     T: DeserializeOwned + Debug
 {
-    let mut len_buf = [0u8; size_of::<usize>()];
-    stream.read_exact(&mut len_buf).await.context("reading exact length")?;
-    let len = usize::from_be_bytes(len_buf);
-    
-    let mut payload = vec![0u8; len];
-    stream.read_exact(&mut payload).await.context("reading exact payload")?;
-    let value = serde_json::from_slice(&payload).context("deserializing payload as JSON")?;
-
-    trace!(len, value:?; "Received bytes over TCP");
-    Ok(value)   
+    read_deserialized_with_limit::<C, _, _>(stream, DEFAULT_MAX_MESSAGE_SIZE).await
 }
 
-/// Write a serialized value to a stream.
+/// Like [`read_deserialized`], but with an explicit cap on the total reassembled message size.
 ///
-/// The payload of the stream contains a header of `size_of::<usize>()` bytes (called `len`). This value indicates the
-/// length of the string being sent next in the stream. `len` bytes are then written to the stream as a string. This
-/// string has been serialized from a generic value using `serde_json`.
-/// 
-pub async fn write_serialized<AW, T>(stream: &mut AW, value: T) -> Result<(), anyhow::Error>
+/// Reading fails as soon as the running total of chunk bodies would exceed `max_message_size`,
+/// before the (potentially huge) payload is ever allocated in full.
+///
+pub async fn read_deserialized_with_limit<C, AR, T>(stream: &mut AR, max_message_size: usize) -> Result<T, anyhow::Error>
 where
+    C: Codec,
+    AR: AsyncRead + Unpin,
+    T: DeserializeOwned + Debug
+{
+    let mut payload = Vec::new();
+    let mut chunk_count = 0usize;
+
+    loop {
+        let mut header = [0u8; size_of::<u32>() + 1];
+        stream.read_exact(&mut header).await.context("reading chunk header")?;
+        let chunk_len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let flag = header[4];
+
+        if chunk_len == 0 && flag != FLAG_FINAL {
+            bail!("received a zero-length chunk that isn't the final chunk");
+        }
+
+        if payload.len() + chunk_len > max_message_size {
+            bail!("reassembled message exceeds max message size of {max_message_size} bytes");
+        }
+
+        chunk_count += 1;
+        if chunk_count > max_message_size / CHUNK_SIZE.max(1) + 1 {
+            bail!("received more chunks than a {max_message_size}-byte message could contain");
+        }
+
+        let mut chunk = vec![0u8; chunk_len];
+        stream.read_exact(&mut chunk).await.context("reading chunk body")?;
+        payload.extend_from_slice(&chunk);
+
+        if flag == FLAG_FINAL {
+            break;
+        }
+    }
+
+    let value = C::decode(&payload).context("decoding payload")?;
+
+    trace!(len = payload.len(), value:?; "Received bytes over TCP");
+    Ok(value)
+}
+
+/// Write a serialized value to a stream framed as a sequence of chunks.
+///
+/// The value is encoded with `C`, then split into chunks of at most [`CHUNK_SIZE`] bytes. Each
+/// chunk is written as a `u32` big-endian length header, a 1-byte flag (`0` if more chunks
+/// follow, `1` if this is the final chunk), and the chunk body. The final chunk is always the
+/// one whose body reaches the end of the payload, including when the payload is empty (a
+/// zero-length final chunk) or an exact multiple of `CHUNK_SIZE` (the last full-size chunk is
+/// itself flagged final, so the reader never hangs waiting for a trailing terminator frame).
+///
+pub async fn write_serialized<C, AW, T>(stream: &mut AW, value: T) -> Result<(), anyhow::Error>
+where
+    C: Codec,
     AW: AsyncWrite + Unpin,
     T: Serialize + Debug
 {
-    let payload = serde_json::to_string(&value).context("serializing payload to JSON")?;
-    let len = payload.len();
-    stream.write(&len.to_be_bytes()).await.context("writing length")?;
-    stream.write_all(payload.as_bytes()).await.context("writing payload")?;
+    let bytes = C::encode(&value).context("encoding payload")?;
+
+    let mut offset = 0;
+    loop {
+        let end = (offset + CHUNK_SIZE).min(bytes.len());
+        let chunk = &bytes[offset..end];
+        let is_final = end == bytes.len();
 
-    trace!(len, payload:?; "Sent bytes over TCP");
+        stream.write_all(&(chunk.len() as u32).to_be_bytes()).await.context("writing chunk length")?;
+        stream.write_all(&[if is_final { FLAG_FINAL } else { FLAG_MORE }]).await.context("writing chunk flag")?;
+        stream.write_all(chunk).await.context("writing chunk body")?;
+
+        offset = end;
+        if is_final {
+            break;
+        }
+    }
+
+    trace!(len = bytes.len(), value:?; "Sent bytes over TCP");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Ping {
+        count: u32,
+        message: String,
+    }
+
+    async fn round_trips<C: Codec>(value: Ping) {
+        // The whole message is written before it is read, so the buffer must hold all of it.
+        let (mut client, mut server) = tokio::io::duplex(CHUNK_SIZE * 4);
+        write_serialized::<C, _, _>(&mut client, value.clone()).await.unwrap();
+        let received: Ping = read_deserialized::<C, _, _>(&mut server).await.unwrap();
+        assert_eq!(received, value);
+    }
+
+    #[tokio::test]
+    async fn json_codec_round_trips_a_value() {
+        round_trips::<JsonCodec>(Ping { count: 1, message: "hello".into() }).await;
+    }
+
+    #[tokio::test]
+    async fn msgpack_codec_round_trips_a_value() {
+        round_trips::<MsgpackCodec>(Ping { count: 2, message: "hello".into() }).await;
+    }
+
+    #[tokio::test]
+    async fn ping_with_empty_message_round_trips() {
+        round_trips::<JsonCodec>(Ping { count: 0, message: String::new() }).await;
+    }
+
+    #[tokio::test]
+    async fn payload_that_is_an_exact_multiple_of_chunk_size_round_trips() {
+        // `{"count":3,"message":""}` is 24 bytes, so this encodes to exactly 2 * CHUNK_SIZE bytes.
+        round_trips::<JsonCodec>(Ping { count: 3, message: "x".repeat(CHUNK_SIZE * 2 - 24) }).await;
+    }
+
+    #[tokio::test]
+    async fn zero_length_non_final_chunk_is_rejected() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&0u32.to_be_bytes()).await.unwrap();
+        client.write_all(&[FLAG_MORE]).await.unwrap();
+
+        let result: Result<Ping, _> = read_deserialized::<JsonCodec, _, _>(&mut server).await;
+        assert!(result.is_err());
+    }
+}
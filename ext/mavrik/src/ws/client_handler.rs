@@ -0,0 +1,161 @@
+use crate::messaging::{MavrikRequest, MavrikResponse, Task, TaskId, TaskResult};
+use crate::service::MavrikService;
+use crate::store::{PullStore, PushStore, QueryStore, SubscribeStore};
+use anyhow::Context;
+use axum::extract::ws::{Message, WebSocket};
+use futures::{SinkExt, StreamExt};
+use log::trace;
+use tokio::select;
+use tokio::task::JoinSet;
+
+#[derive(Debug)]
+pub enum TaskOutputKind {
+    Request(MavrikRequest),
+    TaskResult { seq: u64, task_result: TaskResult },
+    SubscribedTaskResult(TaskResult),
+}
+
+/// A `MavrikService` implementation that speaks the same `MavrikRequest`/`MavrikResponse`
+/// protocol as `TcpClientHandler`, but over a WebSocket connection instead of a raw `TcpStream`.
+///
+/// Each inbound text or binary message is one JSON-serialized `MavrikRequest`; responses are
+/// sent as individual WebSocket frames as they become available, so unlike the TCP transport
+/// there's no length-prefix framing to manage. Subscriptions are handled the same way as over
+/// TCP, which matters most here: a WebSocket connection is what a browser-based dashboard would
+/// actually use to watch the queue.
+pub struct WsClientHandler<Store>
+where
+    Store: SubscribeStore<Error = anyhow::Error>,
+{
+    socket: WebSocket,
+    store: Store,
+    task_results: JoinSet<Result<(u64, TaskResult), anyhow::Error>>,
+    subscription: Option<Store::Completions>
+}
+
+impl<Store> WsClientHandler<Store>
+where
+    Store: PushStore<Id = TaskId, Error = anyhow::Error>
+        + PullStore<Id = TaskId, Error = anyhow::Error>
+        + QueryStore<Error = anyhow::Error>
+        + SubscribeStore<Error = anyhow::Error>
+        + Clone + Send + Sync + 'static,
+{
+    pub fn new(socket: WebSocket, store: Store) -> Self {
+        let task_results = JoinSet::new();
+        Self { socket, store, task_results, subscription: None }
+    }
+
+    /// Awaits the next completion pushed by the active subscription, if any. Mirrors
+    /// `TcpClientHandler::next_subscribed`: pending forever with no active subscription keeps
+    /// this arm from winning a `select!` race until a client actually subscribes.
+    async fn next_subscribed(subscription: &mut Option<Store::Completions>) -> Option<Result<TaskResult, anyhow::Error>> {
+        match subscription {
+            Some(stream) => stream.next().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    async fn send_response(&mut self, response: &MavrikResponse) -> Result<(), anyhow::Error> {
+        trace!(response:?; "Sending response over WebSocket");
+        let payload = serde_json::to_string(response).context("serializing response as JSON")?;
+        self.socket.send(Message::Text(payload.into())).await.context("sending response over WebSocket failed")?;
+        Ok(())
+    }
+
+    async fn handle_request(&mut self, request: MavrikRequest) -> Result<(), anyhow::Error> {
+        let seq = request.seq();
+        match request {
+            MavrikRequest::NewTask { new_task, .. } => {
+                let task = Task::from(new_task);
+                let task_id = self.store.push(task).await.context("store push failed")?;
+                self.send_response(&MavrikResponse::NewTaskId { seq, task_id }).await?;
+            },
+
+            MavrikRequest::AwaitTask { task_id, .. } => {
+                let store = self.store.clone();
+                self.task_results.spawn(async move {
+                    let task_result = store.pull(task_id).await?;
+                    Ok((seq, task_result))
+                });
+            },
+
+            MavrikRequest::GetStoreState { .. } => {
+                let state = self.store.state().await?;
+                self.send_response(&MavrikResponse::StoreState { seq, state }).await?;
+            },
+
+            MavrikRequest::Subscribe { filter, .. } => {
+                self.subscription = Some(self.store.subscribe(filter));
+            },
+
+            MavrikRequest::Unsubscribe { .. } => {
+                self.subscription = None;
+            }
+        };
+        Ok(())
+    }
+
+    async fn handle_task_result(&mut self, seq: u64, task_result: TaskResult) -> Result<(), anyhow::Error> {
+        self.send_response(&MavrikResponse::CompletedTask { seq, task_result }).await
+    }
+
+    /// Pushes a `CompletedTask` response for a task surfaced by the active subscription, rather
+    /// than by a one-shot `AwaitTask`. There's no requesting `seq` to echo back, so `0` is used
+    /// as the sentinel for "unsolicited" completions (mirrors `TcpClientHandler`).
+    async fn handle_subscribed_task_result(&mut self, task_result: TaskResult) -> Result<(), anyhow::Error> {
+        self.handle_task_result(0, task_result).await
+    }
+
+    async fn next_request(&mut self) -> Result<MavrikRequest, anyhow::Error> {
+        loop {
+            let message = self.socket.next().await.context("WebSocket connection closed")?.context("reading WebSocket message failed")?;
+            match message {
+                Message::Text(text) => return serde_json::from_str(&text).context("deserializing request as JSON"),
+                Message::Binary(bytes) => return serde_json::from_slice(&bytes).context("deserializing request as JSON"),
+                Message::Ping(_) | Message::Pong(_) => continue,
+                Message::Close(_) => anyhow::bail!("WebSocket connection closed"),
+            }
+        }
+    }
+}
+
+impl<Store> MavrikService for WsClientHandler<Store>
+where
+    Store: PushStore<Id = TaskId, Error = anyhow::Error>
+        + PullStore<Id = TaskId, Error = anyhow::Error>
+        + QueryStore<Error = anyhow::Error>
+        + SubscribeStore<Error = anyhow::Error>
+        + Clone + Send + Sync + 'static,
+{
+    type TaskOutput = Result<TaskOutputKind, anyhow::Error>;
+
+    async fn poll_task(&mut self) -> Self::TaskOutput {
+        select! {
+            result = self.next_request() => {
+                let request = result.context("receiving Mavrik request over WebSocket failed")?;
+                Ok(TaskOutputKind::Request(request))
+            },
+
+            Some(result) = self.task_results.join_next(), if self.task_results.len() > 0 => {
+                let (seq, task_result) = result
+                    .context("joining task result failed")?
+                    .context("awaiting task failed")?;
+                Ok(TaskOutputKind::TaskResult { seq, task_result })
+            },
+
+            Some(result) = Self::next_subscribed(&mut self.subscription), if self.subscription.is_some() => {
+                let task_result = result.context("awaiting subscribed task result failed")?;
+                Ok(TaskOutputKind::SubscribedTaskResult(task_result))
+            }
+        }
+    }
+
+    async fn on_task_ready(&mut self, output: Self::TaskOutput) -> Result<(), anyhow::Error> {
+        match output? {
+            TaskOutputKind::Request(request) => self.handle_request(request).await,
+            TaskOutputKind::TaskResult { seq, task_result } => self.handle_task_result(seq, task_result).await,
+            TaskOutputKind::SubscribedTaskResult(task_result) => self.handle_subscribed_task_result(task_result).await
+        }
+    }
+}